@@ -2,8 +2,9 @@ use anyhow::{anyhow, Result};
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{params, ToSql};
-use rusqlite::{Connection, Error::QueryReturnedNoRows, NO_PARAMS};
+use rusqlite::{Connection, Error::QueryReturnedNoRows};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
@@ -11,13 +12,16 @@ pub type Pond = Pool<SqliteConnectionManager>;
 
 static SCHEMA: &str = "
 CREATE TABLE IF NOT EXISTS entry (
-  ordinal text NOT NULL PRIMARY KEY,
+  ordinal text NOT NULL,
+  lang    text NOT NULL,
   parent  text,
   ancestor  NUMBER NOT NULL,
   slug    text NOT NULL,
   title   text NOT NULL,
   difficulty NUMBER,
-  content text NOT NULL
+  content text NOT NULL,
+  hash    text NOT NULL,
+  PRIMARY KEY (ordinal, lang)
 );
 ";
 
@@ -45,6 +49,10 @@ impl FromStr for Strategy {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Record {
     pub ordinal: String,
+    /// Output language this row was sourced for. Part of the cache key alongside `ordinal`,
+    /// since a Disk cache covering a multi-language config holds one row per `(ordinal, lang)`.
+    #[serde(skip)]
+    pub lang: String,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub parent: Option<String>,
     #[serde(skip)]
@@ -55,18 +63,29 @@ pub struct Record {
     pub difficulty: Option<u32>,
     #[serde(skip)]
     pub content: String,
+    /// Digest over the raw `metadata.json` bytes and the selected language's content, used to
+    /// detect unchanged entries across incremental rebuilds.
+    #[serde(skip)]
+    pub hash: String,
 }
 
-/// Creates a pool for an in-memory database.
-pub fn connect(strategy: &Strategy) -> Result<Pond> {
-    let manager = match strategy {
-        Strategy::Disk(path) => SqliteConnectionManager::file(path),
-        Strategy::Memory => SqliteConnectionManager::memory(),
+/// Creates a connection pool for the given cache strategy and applies the schema to every pooled
+/// connection as it's opened, so any connection handed out by the pool is immediately usable.
+///
+/// Returns the effective `jobs`, which in turn bounds how many threads can sink concurrently
+/// against the same database. `Strategy::Memory` opens a private, unshared in-memory database
+/// per connection, so pooling more than one is pointless at best and, once two callers start
+/// relying on rows the other wrote, actively wrong; `jobs` is forced to 1 for that strategy
+/// regardless of what's passed in.
+pub fn connect(strategy: &Strategy, jobs: u32) -> Result<(Pond, u32)> {
+    let (manager, jobs) = match strategy {
+        Strategy::Disk(path) => (SqliteConnectionManager::file(path), jobs),
+        Strategy::Memory => (SqliteConnectionManager::memory(), 1),
     };
-    let pool = r2d2::Pool::new(manager)?;
-    pool.get()?.execute(SCHEMA, params![])?;
+    let manager = manager.with_init(|conn| conn.execute_batch(SCHEMA));
+    let pool = r2d2::Pool::builder().max_size(jobs).build(manager)?;
 
-    Ok(pool)
+    Ok((pool, jobs))
 }
 
 /// Executes an arbitrary `select` query against the `entry` table.
@@ -85,12 +104,14 @@ where
     let rows = stmt.query_map(params, |row| {
         Ok(Record {
             ordinal: row.get(0)?,
-            parent: row.get(1)?,
-            ancestor: row.get(2)?,
-            slug: row.get(3)?,
-            title: row.get(4)?,
-            difficulty: row.get(5)?,
-            content: row.get(6)?,
+            lang: row.get(1)?,
+            parent: row.get(2)?,
+            ancestor: row.get(3)?,
+            slug: row.get(4)?,
+            title: row.get(5)?,
+            difficulty: row.get(6)?,
+            content: row.get(7)?,
+            hash: row.get(8)?,
         })
     })?;
 
@@ -101,12 +122,12 @@ where
     Ok(list)
 }
 
-/// Finds the record for the given ordinal.
+/// Finds the record for the given ordinal and language.
 ///
 /// ## Failure
 ///
 /// It fails with a `rusqlite::Error` if the cache is corrupted.
-pub fn select_record(conn: &Connection, ordinal: &str) -> Result<Option<Record>> {
+pub fn select_record(conn: &Connection, ordinal: &str, lang: &str) -> Result<Option<Record>> {
     let result = conn.query_row(
         r#"
         SELECT
@@ -114,18 +135,20 @@ pub fn select_record(conn: &Connection, ordinal: &str) -> Result<Option<Record>>
         FROM
             entry
         WHERE
-            ordinal = ?
+            ordinal = ? AND lang = ?
         "#,
-        &[ordinal],
+        &[ordinal, lang],
         |row| {
             Ok(Record {
                 ordinal: row.get(0)?,
-                parent: row.get(1)?,
-                ancestor: row.get(2)?,
-                slug: row.get(3)?,
-                title: row.get(4)?,
-                difficulty: row.get(5)?,
-                content: row.get(6)?,
+                lang: row.get(1)?,
+                parent: row.get(2)?,
+                ancestor: row.get(3)?,
+                slug: row.get(4)?,
+                title: row.get(5)?,
+                difficulty: row.get(6)?,
+                content: row.get(7)?,
+                hash: row.get(8)?,
             })
         },
     );
@@ -137,51 +160,51 @@ pub fn select_record(conn: &Connection, ordinal: &str) -> Result<Option<Record>>
     }
 }
 
-/// Finds the children entries for the given ordinal.
+/// Finds the children entries for the given ordinal and language.
 ///
 /// ## Failure
 ///
 /// It fails with a `rusqulite::Error` if the cache is corrupted.
-pub fn select_children(conn: &Connection, ordinal: &str) -> Result<Vec<Record>> {
+pub fn select_children(conn: &Connection, ordinal: &str, lang: &str) -> Result<Vec<Record>> {
     let query = r#"
         SELECT
             *
         FROM
             entry
         WHERE
-            parent IS ?
+            parent IS ? AND lang = ?
         ORDER BY
             ordinal;
     "#;
 
-    let list = select_records(conn, query, &[ordinal])?;
+    let list = select_records(conn, query, &[ordinal, lang])?;
 
     Ok(list)
 }
 
-/// Finds the section entries. A 'section' is the top level classifier identified by a single
-/// digit ordinal and no parent.
+/// Finds the section entries for the given language. A 'section' is the top level classifier
+/// identified by a single digit ordinal and no parent.
 ///
 /// ## Failure
 ///
 /// It fails with a `rusqulite::Error` if the cache is corrupted.
-pub fn select_sections(conn: &Connection) -> Result<Vec<Record>> {
+pub fn select_sections(conn: &Connection, lang: &str) -> Result<Vec<Record>> {
     let query = r#"
         SELECT
             *
         FROM
             entry
         WHERE
-            parent IS NULL
+            parent IS NULL AND lang = ?
         ORDER BY
             ordinal;
     "#;
-    let list = select_records(conn, query, NO_PARAMS)?;
+    let list = select_records(conn, query, &[lang])?;
 
     Ok(list)
 }
 
-/// Finds the sibling entries for the given ordinal.
+/// Finds the sibling entries for the given ordinal and language.
 ///
 /// ## Failure
 ///
@@ -189,6 +212,7 @@ pub fn select_sections(conn: &Connection) -> Result<Vec<Record>> {
 pub fn select_siblings(
     conn: &Connection,
     ordinal: &str,
+    lang: &str,
 ) -> Result<(Option<Record>, Option<Record>)> {
     let trail: Vec<&str> = ordinal.split('.').collect();
     let upbound = trail.len() - 1;
@@ -199,29 +223,182 @@ pub fn select_siblings(
     let prev_ordinal = format!("{}.{}", trail[0..upbound].join("."), prev_index);
     let next_ordinal = format!("{}.{}", trail[0..upbound].join("."), next_index);
 
-    let prev = select_record(conn, &prev_ordinal)?;
-    let next = select_record(conn, &next_ordinal)?;
+    let prev = select_record(conn, &prev_ordinal, lang)?;
+    let next = select_record(conn, &next_ordinal, lang)?;
 
     Ok((prev, next))
 }
 
+/// Inserts every record in `records` inside a single transaction, committing once at the end.
+///
+/// Batching this way avoids the fsync-per-row cost of autocommitted inserts, which matters most
+/// for the `Disk` strategy.
+pub fn insert_iter<'a, I>(conn: &mut Connection, records: I) -> Result<()>
+where
+    I: IntoIterator<Item = &'a Record>,
+{
+    let tx = conn.transaction()?;
+
+    for record in records {
+        insert_record(&tx, record)?;
+    }
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Inserts or, for a row already cached under the same `(ordinal, lang)`, replaces it.
+///
+/// Replacing rather than plain-inserting lets the `Disk` strategy reuse an existing database
+/// across runs: an entry whose hash changed since the last build overwrites its stale row
+/// instead of tripping the `(ordinal, lang)` primary key constraint.
 pub fn insert_record(conn: &Connection, record: &Record) -> Result<()> {
-    let values: [&dyn rusqlite::ToSql; 7] = [
+    let values: [&dyn rusqlite::ToSql; 9] = [
         &record.ordinal,
+        &record.lang,
         &record.parent,
         &record.ancestor,
         &record.slug,
         &record.title,
         &record.difficulty,
         &record.content,
+        &record.hash,
     ];
     conn.execute(
         r#"
-        INSERT INTO entry
-        (ordinal, parent, ancestor, slug, title, difficulty, content)
-        VALUES (?, ?, ?, ?, ?, ?, ?);
+        INSERT OR REPLACE INTO entry
+        (ordinal, lang, parent, ancestor, slug, title, difficulty, content, hash)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?);
     "#,
         &values,
     )?;
     Ok(())
 }
+
+/// Deletes the row cached under the given `(ordinal, lang)`, if any.
+///
+/// ## Failure
+///
+/// It fails with a `rusqlite::Error` if the cache is corrupted.
+pub fn delete_record(conn: &Connection, ordinal: &str, lang: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM entry WHERE ordinal = ? AND lang = ?;",
+        &[ordinal, lang],
+    )?;
+
+    Ok(())
+}
+
+/// Finds every ordinal cached for the given language.
+///
+/// ## Failure
+///
+/// It fails with a `rusqlite::Error` if the cache is corrupted.
+pub fn select_ordinals(conn: &Connection, lang: &str) -> Result<HashSet<String>> {
+    let mut stmt = conn.prepare("SELECT ordinal FROM entry WHERE lang = ?;")?;
+
+    let mut set = HashSet::new();
+    let rows = stmt.query_map(&[lang], |row| row.get(0))?;
+
+    for result in rows {
+        set.insert(result?);
+    }
+
+    Ok(set)
+}
+
+/// Expands a set of changed ordinals into the full set of ordinals that must be re-sunk: the
+/// changed entries themselves, their ancestor chain (so a changed leaf refreshes its parent's
+/// table of contents), and their immediate siblings (so prev/next navigation stays correct).
+///
+/// ## Failure
+///
+/// It fails with a `rusqlite::Error` if the cache is corrupted.
+pub fn expand_dirty(
+    conn: &Connection,
+    changed: &HashSet<String>,
+    lang: &str,
+) -> Result<HashSet<String>> {
+    let mut dirty = HashSet::new();
+
+    for ordinal in changed {
+        dirty.insert(ordinal.clone());
+
+        let mut parent = select_record(conn, ordinal, lang)?.and_then(|record| record.parent);
+        while let Some(ordinal) = parent {
+            dirty.insert(ordinal.clone());
+            parent = select_record(conn, &ordinal, lang)?.and_then(|record| record.parent);
+        }
+
+        let (prev, next) = select_siblings(conn, ordinal, lang)?;
+        dirty.extend(prev.map(|record| record.ordinal));
+        dirty.extend(next.map(|record| record.ordinal));
+    }
+
+    Ok(dirty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(ordinal: &str, lang: &str, parent: Option<&str>) -> Record {
+        Record {
+            ordinal: ordinal.to_string(),
+            lang: lang.to_string(),
+            parent: parent.map(str::to_string),
+            ancestor: 1,
+            slug: ordinal.to_string(),
+            title: ordinal.to_string(),
+            difficulty: None,
+            content: String::new(),
+            hash: "hash".to_string(),
+        }
+    }
+
+    #[test]
+    fn expand_dirty_includes_ancestors_and_siblings() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute(SCHEMA, params![])?;
+
+        insert_record(&conn, &record("1", "en", None))?;
+        insert_record(&conn, &record("1.1", "en", Some("1")))?;
+        insert_record(&conn, &record("1.2", "en", Some("1")))?;
+        insert_record(&conn, &record("1.3", "en", Some("1")))?;
+
+        let changed: HashSet<String> = vec!["1.2".to_string()].into_iter().collect();
+        let dirty = expand_dirty(&conn, &changed, "en")?;
+
+        assert_eq!(
+            dirty,
+            vec!["1.2", "1", "1.1", "1.3"]
+                .into_iter()
+                .map(str::to_string)
+                .collect()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn expand_dirty_is_scoped_to_lang() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute(SCHEMA, params![])?;
+
+        insert_record(&conn, &record("1", "en", None))?;
+        insert_record(&conn, &record("1.1", "en", Some("1")))?;
+        insert_record(&conn, &record("1", "ca", None))?;
+        insert_record(&conn, &record("1.1", "ca", Some("1")))?;
+
+        let changed: HashSet<String> = vec!["1.1".to_string()].into_iter().collect();
+        let dirty = expand_dirty(&conn, &changed, "en")?;
+
+        assert_eq!(
+            dirty,
+            vec!["1.1", "1"].into_iter().map(str::to_string).collect()
+        );
+
+        Ok(())
+    }
+}
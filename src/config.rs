@@ -0,0 +1,33 @@
+//! Optional config file for config-driven, multi-language builds.
+
+use anyhow::Result;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// One configured output language and the directory its tree is written under.
+#[derive(Debug, Deserialize)]
+pub struct LanguageOutput {
+    pub lang: String,
+    pub output_path: PathBuf,
+}
+
+/// Typed shape of the `--config` file: an input path, the languages to build and where each
+/// goes, and the directory entry names to skip while sourcing.
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub input_path: Option<PathBuf>,
+    #[serde(default)]
+    pub languages: Vec<LanguageOutput>,
+    #[serde(default)]
+    pub excluded_names: Option<Vec<String>>,
+}
+
+/// Reads and parses the config file at `path`.
+pub fn load(path: &Path) -> Result<Config> {
+    let data = fs::read_to_string(path)?;
+    let config: Config = serde_yaml::from_str(&data)?;
+
+    Ok(config)
+}
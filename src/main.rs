@@ -1,64 +1,142 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::{AppSettings, Clap};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::thread;
 
 mod cache;
+mod config;
 mod sink;
 mod source;
-use cache::{select_sections, Strategy};
-use sink::write_tree;
+use cache::{expand_dirty, select_record, select_sections, Pond, Strategy};
+use sink::{build_search_index, remove_output, write_tree};
 use source::read_entries;
 
-fn run<I, O>(input: I, output: O, lang: &str, strategy: Strategy) -> Result<()>
+const DEFAULT_EXCLUDED_NAMES: &[&str] = &["assets", "temario.md"];
+
+/// Sources and sinks a single language into `output`, reusing the given `pond`.
+///
+/// `jobs` must not exceed `pond`'s connection limit: it bounds both the number of sections sunk
+/// concurrently and, one-to-one, the pooled connections handed out to do it.
+fn run_language<I, O>(
+    pond: Pond,
+    input: I,
+    output: O,
+    lang: &str,
+    excluded_names: &[&str],
+    search_index: bool,
+    force: bool,
+    jobs: u32,
+) -> Result<()>
 where
     I: AsRef<Path>,
     O: AsRef<Path>,
 {
-    let excluded_names = vec!["assets", "temario.md"];
+    // Sourcing phase
+    let (changed, pruned) = read_entries(pond.clone(), input, excluded_names, lang, force)?;
 
-    // let source = "../sangaku_manasource/src";
-    // let target = Path::new("content");
-    // let lang = "en";
-    // let strategy = Strategy::Memory;
-    // let storage = Strategy::Disk(Path::new("test.db").to_path_buf());
+    let conn = pond.get()?;
+    let mut sections = select_sections(&conn, lang)?;
+    let dirty = expand_dirty(&conn, &changed, lang)?;
 
-    let pond = cache::connect(&strategy)?;
+    fs::create_dir_all(&output)?;
 
-    // Sourcing phase
-    read_entries(pond.clone(), input, &excluded_names, lang)?;
+    for record in &pruned {
+        match &record.parent {
+            None => remove_output(output.as_ref(), record, None)?,
+            Some(_) => {
+                if let Some(section) = select_record(&conn, &record.ancestor.to_string(), lang)? {
+                    remove_output(output.as_ref(), record, Some(&section.slug))?;
+                }
+            }
+        }
+    }
 
-    let conn = pond.get()?;
-    let sections = select_sections(&conn)?;
+    if search_index {
+        let index = build_search_index(&conn, &sections)?;
+        fs::write(&output.as_ref().join("search-index.json"), &index)?;
+    }
+    drop(conn);
 
-    // Sinking phase
-    fs::create_dir(&output)?;
-    for entry in sections {
-        let section = output.as_ref().join(&entry.slug);
+    // Sinking phase: sections are sunk `jobs` at a time, each worker pulling its own pooled
+    // connection, so both thread count and concurrent connections stay within `--jobs`.
+    let output = output.as_ref().to_path_buf();
 
-        fs::create_dir(&section)?;
+    while !sections.is_empty() {
+        let batch_size = sections.len().min(jobs as usize);
 
-        write_tree(&conn, &entry, &section)?;
+        sections
+            .drain(..batch_size)
+            .map(|entry| {
+                let pond = pond.clone();
+                let section = output.join(&entry.slug);
+                let dirty = dirty.clone();
+                fs::create_dir_all(&section).unwrap();
+
+                thread::spawn(move || -> Result<()> {
+                    let conn = pond.get()?;
+                    write_tree(&conn, &entry, &section, &dirty)
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .try_for_each(|handle| handle.join().unwrap())?;
     }
 
-    // sections
-    //     .into_iter()
-    //     .map(|entry| {
-    //         let pond = pond.clone();
-    //         let section = target.join(&entry.slug);
-    //         fs::create_dir(&section).unwrap();
-
-    //         thread::spawn(move || {
-    //             let conn = pond.get().unwrap();
-    //             write_tree(&conn, &entry, &section).unwrap();
-    //         })
-    //     })
-    //     .collect::<Vec<_>>()
-    //     .into_iter()
-    //     .map(thread::JoinHandle::join)
-    //     .collect::<std::result::Result<(), _>>()
-    //     .unwrap();
+    Ok(())
+}
+
+/// Resolves the effective settings from `cli`, layering an optional `--config` file underneath
+/// explicit CLI flags, and runs every configured language against a single shared `Pond`.
+fn run(cli: Cli) -> Result<()> {
+    let config = cli.config.as_deref().map(config::load).transpose()?;
+
+    let input_path = cli
+        .input_path
+        .or_else(|| config.as_ref().and_then(|c| c.input_path.clone()))
+        .ok_or_else(|| anyhow!("an input path is required via --input-path or a config file"))?;
+
+    let excluded_names: Vec<String> = config
+        .as_ref()
+        .and_then(|c| c.excluded_names.clone())
+        .unwrap_or_else(|| {
+            DEFAULT_EXCLUDED_NAMES
+                .iter()
+                .map(|name| name.to_string())
+                .collect()
+        });
+    let excluded_names: Vec<&str> = excluded_names.iter().map(String::as_str).collect();
+
+    let languages: Vec<(String, PathBuf)> = match (cli.lang, cli.output_path) {
+        (Some(lang), Some(output_path)) => vec![(lang, output_path)],
+        _ => config
+            .as_ref()
+            .map(|c| {
+                c.languages
+                    .iter()
+                    .map(|language| (language.lang.clone(), language.output_path.clone()))
+                    .collect()
+            })
+            .filter(|languages: &Vec<_>| !languages.is_empty())
+            .ok_or_else(|| {
+                anyhow!("at least one output language is required via --lang/--output-path or a config file")
+            })?,
+    };
+
+    let (pond, jobs) = cache::connect(&cli.cache_path, cli.jobs)?;
+
+    for (lang, output_path) in languages {
+        run_language(
+            pond.clone(),
+            &input_path,
+            &output_path,
+            &lang,
+            &excluded_names,
+            cli.search_index,
+            cli.force,
+            jobs,
+        )?;
+    }
 
     Ok(())
 }
@@ -72,20 +150,35 @@ struct Cli {
     /// already exists.
     #[clap(long, short = 'c', value_name = "path", default_value = ":memory:")]
     cache_path: Strategy,
-    /// Input directory. Expects a valid mana source
+    /// Input directory. Expects a valid mana source. Overrides `input_path` from --config
     #[clap(long, short = 'i', value_name = "path")]
-    input_path: PathBuf,
-    /// Output directory
+    input_path: Option<PathBuf>,
+    /// Output directory for a single language. Combined with --lang, overrides the language
+    /// list from --config
     #[clap(long, short = 'o', value_name = "path")]
-    output_path: PathBuf,
-    /// Output language
-    #[clap(long, value_name = "code", default_value = "en", possible_values = &["en", "ca", "es"])]
-    lang: String,
+    output_path: Option<PathBuf>,
+    /// Output language for a single language. Combined with --output-path, overrides the
+    /// language list from --config
+    #[clap(long, value_name = "code", possible_values = &["en", "ca", "es"])]
+    lang: Option<String>,
+    /// Config file listing the input path, the output languages and their paths, and excluded
+    /// names. CLI flags override individual config keys when both are given
+    #[clap(long, value_name = "path")]
+    config: Option<PathBuf>,
+    /// Generate a client-side full-text search-index.json alongside the Markdown output
+    #[clap(long)]
+    search_index: bool,
+    /// Maximum number of threads used to sink sections concurrently
+    #[clap(long, short = 'j', value_name = "n", default_value = "4")]
+    jobs: u32,
+    /// Bypass the content-hash check and rebuild every entry, even if unchanged
+    #[clap(long)]
+    force: bool,
 }
 
 fn main() {
     let cli: Cli = Cli::parse();
-    match run(cli.input_path, cli.output_path, &cli.lang, cli.cache_path) {
+    match run(cli) {
         Ok(_) => {}
         Err(err) => {
             eprintln!("{:?}", err);
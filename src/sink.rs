@@ -2,10 +2,14 @@
 
 use anyhow::Result;
 use rusqlite::Connection;
+use serde::Serialize;
+use serde_json;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use super::cache::{select_children, select_siblings, Record};
+use super::source::fold_case;
 
 pub fn build_metadata(data: &mut String, record: &Record) -> Result<()> {
     let blob = serde_yaml::to_string(record)?;
@@ -17,10 +21,10 @@ pub fn build_metadata(data: &mut String, record: &Record) -> Result<()> {
 
 /// Builds either a node or a leaf.
 pub fn build_content(conn: &Connection, record: &Record) -> Result<(String, Vec<Record>)> {
-    let children = select_children(conn, &record.ordinal)?;
+    let children = select_children(conn, &record.ordinal, &record.lang)?;
 
     let data = if children.is_empty() {
-        let siblings = select_siblings(&conn, &record.ordinal)?;
+        let siblings = select_siblings(&conn, &record.ordinal, &record.lang)?;
 
         build_leaf(&record, siblings)?
     } else {
@@ -73,28 +77,205 @@ pub fn build_leaf(record: &Record, siblings: (Option<Record>, Option<Record>)) -
 }
 
 /// Takes a record, walks through the dependent tree and writes to a file.
-pub fn write_tree(conn: &Connection, record: &Record, path: &PathBuf) -> Result<()> {
+///
+/// Only records present in `dirty` are actually written; the tree is still walked in full so
+/// that dirty descendants further down get refreshed.
+pub fn write_tree(
+    conn: &Connection,
+    record: &Record,
+    path: &PathBuf,
+    dirty: &HashSet<String>,
+) -> Result<()> {
     let (data, children) = build_content(conn, &record)?;
 
-    fs::write(&path.join("index.md"), &data)?;
+    if dirty.contains(&record.ordinal) {
+        fs::write(&path.join("index.md"), &data)?;
+    }
 
     for child in children {
-        write_node(conn, &child, &path)?;
+        write_node(conn, &child, &path, dirty)?;
     }
 
     Ok(())
 }
 
 /// Takes a record, walks through the dependent tree and writes to a file.
-pub fn write_node(conn: &Connection, record: &Record, path: &PathBuf) -> Result<()> {
+///
+/// Only records present in `dirty` are actually written; the tree is still walked in full so
+/// that dirty descendants further down get refreshed.
+pub fn write_node(
+    conn: &Connection,
+    record: &Record,
+    path: &PathBuf,
+    dirty: &HashSet<String>,
+) -> Result<()> {
     let (data, children) = build_content(conn, &record)?;
     let filename = format!("{}.md", record.slug);
 
-    fs::write(&path.join(&filename), &data)?;
+    if dirty.contains(&record.ordinal) {
+        fs::write(&path.join(&filename), &data)?;
+    }
 
     for child in children {
-        write_node(conn, &child, &path)?;
+        write_node(conn, &child, &path, dirty)?;
+    }
+
+    Ok(())
+}
+
+/// Removes the output written for a record pruned from the cache.
+///
+/// A top-level section (`section_slug: None`) owns its own directory (`output.join(&record.slug)`),
+/// which is removed wholesale. Any other record shares its section's directory and was written as
+/// `{slug}.md` inside it, identified by `section_slug`. Does nothing if the path was never
+/// written in the first place.
+pub fn remove_output(output: &Path, record: &Record, section_slug: Option<&str>) -> Result<()> {
+    let path = match section_slug {
+        Some(section_slug) => output.join(section_slug).join(format!("{}.md", record.slug)),
+        None => output.join(&record.slug),
+    };
+
+    if !path.exists() {
+        return Ok(());
+    }
+
+    match section_slug {
+        Some(_) => fs::remove_file(&path)?,
+        None => fs::remove_dir_all(&path)?,
+    }
+
+    Ok(())
+}
+
+/// A document entry in the search index, referenced by postings via its position in `docs`.
+#[derive(Debug, Serialize)]
+struct SearchDoc {
+    slug: String,
+    ordinal: String,
+    title: String,
+}
+
+/// The inverted index written to `search-index.json`.
+///
+/// `index` maps a token to its postings, each a `(docId, term frequency)` pair pointing into
+/// `docs`, so a client can rank by summed tf across query terms without re-tokenizing anything.
+#[derive(Debug, Serialize)]
+struct SearchIndex {
+    docs: Vec<SearchDoc>,
+    index: HashMap<String, Vec<(usize, u32)>>,
+}
+
+/// Splits on non-alphanumeric boundaries, folds case/accents, and drops tokens shorter than 2
+/// chars.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|ch: char| !ch.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(fold_case)
+        .filter(|token| token.chars().count() >= 2)
+}
+
+/// Walks `record` and its descendants, folding their title/content tokens into `docs`/`index`.
+fn index_record(
+    conn: &Connection,
+    record: &Record,
+    docs: &mut Vec<SearchDoc>,
+    index: &mut HashMap<String, Vec<(usize, u32)>>,
+) -> Result<()> {
+    let doc_id = docs.len();
+    docs.push(SearchDoc {
+        slug: record.slug.clone(),
+        ordinal: record.ordinal.clone(),
+        title: record.title.clone(),
+    });
+
+    let mut term_frequency: HashMap<String, u32> = HashMap::new();
+    for token in tokenize(&record.title).chain(tokenize(&record.content)) {
+        *term_frequency.entry(token).or_insert(0) += 1;
+    }
+
+    for (token, tf) in term_frequency {
+        index.entry(token).or_insert_with(Vec::new).push((doc_id, tf));
+    }
+
+    for child in select_children(conn, &record.ordinal, &record.lang)? {
+        index_record(conn, &child, docs, index)?;
     }
 
     Ok(())
 }
+
+/// Builds a client-side full-text search index covering `records` and all their descendants.
+///
+/// Serializes to `{ "docs": [...], "index": { "term": [[docId, tf], ...] } }` so a small
+/// front-end can rank matches by summed tf across the query's terms.
+pub fn build_search_index(conn: &Connection, records: &[Record]) -> Result<String> {
+    let mut docs = Vec::new();
+    let mut index = HashMap::new();
+
+    for record in records {
+        index_record(conn, record, &mut docs, &mut index)?;
+    }
+
+    let search_index = SearchIndex { docs, index };
+
+    Ok(serde_json::to_string(&search_index)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::cache::{connect, insert_record, Strategy};
+
+    #[test]
+    fn tokenize_folds_case_and_drops_short_tokens() {
+        let tokens: Vec<String> = tokenize("El Camí, de l'Àrbre!").collect();
+
+        assert_eq!(tokens, vec!["el", "cami", "de", "arbre"]);
+    }
+
+    fn record(
+        ordinal: &str,
+        lang: &str,
+        parent: Option<&str>,
+        title: &str,
+        content: &str,
+    ) -> Record {
+        Record {
+            ordinal: ordinal.to_string(),
+            lang: lang.to_string(),
+            parent: parent.map(str::to_string),
+            ancestor: 1,
+            slug: ordinal.to_string(),
+            title: title.to_string(),
+            difficulty: None,
+            content: content.to_string(),
+            hash: "hash".to_string(),
+        }
+    }
+
+    #[test]
+    fn build_search_index_covers_records_and_descendants() -> Result<()> {
+        let (pond, _) = connect(&Strategy::Memory, 1)?;
+        let conn = pond.get()?;
+
+        let section = record("1", "en", None, "Numbers", "About numbers");
+        let child = record("1.1", "en", Some("1"), "Fractions", "Half of a whole");
+
+        insert_record(&conn, &section)?;
+        insert_record(&conn, &child)?;
+
+        let raw = build_search_index(&conn, &[section])?;
+        let parsed: serde_json::Value = serde_json::from_str(&raw)?;
+
+        let docs = parsed["docs"].as_array().unwrap();
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0]["ordinal"], "1");
+        assert_eq!(docs[1]["ordinal"], "1.1");
+
+        let postings = parsed["index"]["fractions"].as_array().unwrap();
+        assert_eq!(postings.len(), 1);
+        assert_eq!(postings[0][0], 1);
+
+        Ok(())
+    }
+}
@@ -27,14 +27,16 @@
 //! ```
 
 use anyhow::Result;
-use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
 use serde_json;
+use std::collections::HashSet;
 use std::fs;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
 
-use super::cache::{insert_record, Pond, Record};
+use super::cache::{delete_record, insert_iter, select_ordinals, select_record, Pond, Record};
 
 #[derive(Debug, Serialize, Deserialize)]
 struct MetaItem {
@@ -60,22 +62,44 @@ fn exclude_by_name(name: &str, excluded_names: &[&str]) -> bool {
         .is_none()
 }
 
+/// Transliterates accented Latin letters to their ASCII base and lowercases the result.
+///
+/// Normalizes to Unicode NFD and drops the combining marks left behind (e.g. `ñ` decomposes to
+/// `n` plus a combining tilde), so `ca`/`es` text can be folded down to plain ASCII elsewhere
+/// (slugs, search tokens) without losing accented letters outright.
+pub(crate) fn fold_case(input: &str) -> String {
+    input
+        .nfd()
+        .filter(|ch| !is_combining_mark(*ch))
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
 /// Tranforms the given string into its equivalent with ASCII lowercase `a..z` and `-` instead of
 /// spaces.
 fn slug(input: &str) -> String {
-    input
-        .to_ascii_lowercase()
-        .to_lowercase()
+    let collapsed = fold_case(input)
         .chars()
         .filter_map(|ch| match ch {
             'a'..='z' | '-' => Some(ch),
             ' ' => Some('-'),
             _ => None,
         })
-        .collect()
+        .fold(String::new(), |mut acc, ch| {
+            if ch == '-' && acc.ends_with('-') {
+                acc
+            } else {
+                acc.push(ch);
+                acc
+            }
+        });
+
+    collapsed.trim_matches('-').to_string()
 }
 
-fn process_entry(conn: &Connection, path: &PathBuf, lang: &str) -> Result<()> {
+/// Processes a single entry directory, returning its ordinal (present regardless of whether the
+/// entry has content for `lang`) alongside the `Record` for it, if any.
+fn process_entry(path: &PathBuf, lang: &str) -> Result<(String, Option<Record>)> {
     let mut handle = fs::File::open(path.join("metadata.json"))?;
     let mut data = String::new();
     handle.read_to_string(&mut data)?;
@@ -102,39 +126,103 @@ fn process_entry(conn: &Connection, path: &PathBuf, lang: &str) -> Result<()> {
 
             data
         };
+        let hash = {
+            let mut bytes = data.into_bytes();
+            bytes.extend_from_slice(content.as_bytes());
+
+            blake3::hash(&bytes).to_hex().to_string()
+        };
         let record = Record {
-            ordinal,
+            ordinal: ordinal.clone(),
+            lang: lang.to_string(),
             parent,
             ancestor,
             slug,
             title,
             difficulty,
             content,
+            hash,
         };
 
-        insert_record(conn, &record)?;
+        Ok((ordinal, Some(record)))
     } else {
         println!("Skipping {}. No content for {}.", &ordinal, &lang);
-    }
 
-    Ok(())
+        Ok((ordinal, None))
+    }
 }
 
-/// Reads the section directories from the given mana path and processes every entry found in them
-/// for the given language.
-pub fn read_entries<S>(pond: Pond, source: S, excluded_names: &[&str], lang: &str) -> Result<()>
+/// Reads the section directories from the given mana path, processes every entry found in them
+/// for the given language, and inserts the ones that are new or changed inside a single
+/// transaction.
+///
+/// Returns the ordinals of the entries that were inserted, so the sink phase can skip rebuilding
+/// output that didn't change, alongside the records pruned from the cache. Unless `force` is set,
+/// an entry whose hash matches the one already cached for its `(ordinal, lang)` is left untouched
+/// and reported as clean. Ordinals cached for `lang` that no longer have a source directory are
+/// pruned: their cache row is deleted, their parent (if any) is folded into the returned changed
+/// set so its table of contents gets regenerated without them, and the stale record itself is
+/// returned so the caller can remove the output it once produced.
+pub fn read_entries<S>(
+    pond: Pond,
+    source: S,
+    excluded_names: &[&str],
+    lang: &str,
+    force: bool,
+) -> Result<(HashSet<String>, Vec<Record>)>
 where
     S: AsRef<Path>,
 {
+    let mut seen_ordinals = HashSet::new();
+    let mut records = Vec::new();
+
     for entry in fs::read_dir(source)? {
         let entry = entry?.path();
         let name = entry.as_path().file_name().unwrap().to_str().unwrap();
-        let conn = pond.get()?;
 
         if exclude_by_name(name, &excluded_names) {
-            process_entry(&conn, &entry, lang)?;
+            let (ordinal, record) = process_entry(&entry, lang)?;
+            seen_ordinals.insert(ordinal);
+
+            if let Some(record) = record {
+                records.push(record);
+            }
         }
     }
 
-    Ok(())
+    let conn = pond.get()?;
+    let mut changed = HashSet::new();
+    let mut pruned = Vec::new();
+
+    for stale_ordinal in select_ordinals(&conn, lang)?.difference(&seen_ordinals) {
+        println!("Pruning {}. No longer present in the source.", stale_ordinal);
+
+        if let Some(stale_record) = select_record(&conn, stale_ordinal, lang)? {
+            changed.extend(stale_record.parent.clone());
+            pruned.push(stale_record);
+        }
+
+        delete_record(&conn, stale_ordinal, lang)?;
+    }
+
+    let mut dirty_records = Vec::new();
+
+    for record in records {
+        let is_clean = !force
+            && select_record(&conn, &record.ordinal, lang)?
+                .map_or(false, |existing| existing.hash == record.hash);
+
+        if is_clean {
+            println!("Skipping {}. Unchanged since the last build.", &record.ordinal);
+        } else {
+            changed.insert(record.ordinal.clone());
+            dirty_records.push(record);
+        }
+    }
+    drop(conn);
+
+    let mut conn = pond.get()?;
+    insert_iter(&mut conn, &dirty_records)?;
+
+    Ok((changed, pruned))
 }